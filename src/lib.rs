@@ -1,6 +1,8 @@
-use std::io::{Error, ErrorKind};
-use std::process::{Command, Stdio, ExitStatus, Child};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::fs::{File, OpenOptions};
+use std::process::{Command, Stdio, ExitStatus, Child, Output};
 use std::collections::HashMap;
+use std::thread;
 
 pub type FunResult = Result<String, std::io::Error>;
 pub type CmdResult = Result<(), std::io::Error>;
@@ -163,6 +165,23 @@ macro_rules! run_fun {
    };
 }
 
+/// ## run_fun_all! --> Result<FullOutput, Error>
+/// ```rust
+/// let output = run_fun_all!("grep foo bar.txt")?;
+/// if !output.status.success() {
+///     warn!("grep failed: {}", output.stderr.trim());
+/// }
+/// ```
+#[macro_export]
+macro_rules! run_fun_all {
+   ($cmd:ident $($arg:tt)*) => {
+       $crate::run_fun_all(&$crate::macro_str!(run_fun_all))
+   };
+   ($($arg:tt)*) => {
+       $crate::run_fun_all(&format!($($arg)*))
+   };
+}
+
 
 ///
 /// ## run_cmd! --> CmdResult
@@ -184,6 +203,12 @@ macro_rules! run_fun {
 ///     date;
 ///     ls -l ${file};
 /// }
+///
+/// // && and || short-circuit like in a real shell
+/// run_cmd!("make && make install || echo install failed");
+///
+/// // a trailing & detaches the command and keeps going without waiting for it
+/// run_cmd!("sleep 10 &");
 /// ```
 #[macro_export]
 macro_rules! run_cmd {
@@ -198,7 +223,7 @@ macro_rules! run_cmd {
     (&$st:expr; $var:ident; $($arg:tt)*) => {{
         $st.insert(stringify!($var).into(), format!("{}", $var));
         let src = $crate::macro_str!(run_cmd);
-        $crate::run_cmd(&$crate::resolve_name(&src, &$st, &file!(), line!()))
+        $crate::run_cmd_with_vars(&src, &$st, &file!(), line!())
     }};
     ($cmd:ident $($arg:tt)*) => {{
         $crate::run_cmd(&$crate::macro_str!(run_cmd))
@@ -208,12 +233,454 @@ macro_rules! run_cmd {
     }};
 }
 
+///
+/// ## spawn_cmd! --> Result<Job, Error>
+/// ```rust
+/// // starts the pipeline and returns immediately with a handle to it
+/// let job = spawn_cmd!("sleep 3 | echo done")?;
+/// // ... do other work while it runs ...
+/// job.wait()?;
+/// ```
+///
+/// Takes a single pipeline (stages joined by `|`, with redirections). `;`,
+/// `&&`, `||` and `&` do not split it into separate commands: they are
+/// dropped, and the words around them end up in one command's argv.
+#[macro_export]
+macro_rules! spawn_cmd {
+    (use $($arg:tt)*) => {{
+        let mut sym_table = ::std::collections::HashMap::new();
+        spawn_cmd!(&sym_table; $($arg)*)
+    }};
+    (&$st:expr; $var:ident, $($arg:tt)*) => {{
+        $st.insert(stringify!($var).into(), format!("{}", $var));
+        spawn_cmd!(&$st; $($arg)*)
+    }};
+    (&$st:expr; $var:ident; $($arg:tt)*) => {{
+        $st.insert(stringify!($var).into(), format!("{}", $var));
+        let src = $crate::macro_str!(spawn_cmd);
+        $crate::spawn_cmd_with_vars(&src, &$st, &file!(), line!())
+    }};
+    ($cmd:ident $($arg:tt)*) => {{
+        $crate::spawn_cmd(&$crate::macro_str!(spawn_cmd))
+    }};
+    ($($arg:tt)*) => {{
+        $crate::spawn_cmd(&format!($($arg)*))
+    }};
+}
+
+/// ## spawn_fun! --> Result<Job, Error>
+/// ```rust
+/// let job = spawn_fun!("echo the quick brown fox jumped over the lazy dog | wc -w")?;
+/// let n = job.wait_fun()?;
+/// info!("There are {} words in above sentence", n.trim());
+/// ```
+#[macro_export]
+macro_rules! spawn_fun {
+   ($cmd:ident $($arg:tt)*) => {
+       $crate::spawn_fun(&$crate::macro_str!(spawn_fun))
+   };
+   ($($arg:tt)*) => {
+       $crate::spawn_fun(&format!($($arg)*))
+   };
+}
+
+/// A redirect target for `>`, `>>`, `2>` or `2>>`: either truncate or append the file.
+#[derive(Debug, Clone)]
+enum RedirectTarget {
+    Truncate(String),
+    Append(String),
+}
+
+/// The redirections attached to a single `Command` in the AST.
+#[derive(Debug, Default, Clone)]
+struct Redirects {
+    stdin: Option<String>,
+    stdout: Option<RedirectTarget>,
+    stderr: Option<RedirectTarget>,
+    stderr_to_stdout: bool,
+}
+
+fn open_redirect_target(target: &RedirectTarget) -> std::io::Result<File> {
+    match target {
+        RedirectTarget::Truncate(path) => File::create(path),
+        RedirectTarget::Append(path) => OpenOptions::new().create(true).append(true).open(path),
+    }
+}
+
+// Wires up a stage's stdout/stderr per its redirects, defaulting stdout to a pipe
+// so it can feed the next stage, and stderr to a pipe too so it can be captured
+// instead of silently inheriting the parent's. Stdin is handled by the caller,
+// since it differs between the head of a pipeline and the stages that follow it.
+fn configure_stdout_stderr(cmd: &mut Command, redirects: &Redirects) -> std::io::Result<()> {
+    let stdout_file = match &redirects.stdout {
+        Some(target) => Some(open_redirect_target(target)?),
+        None => None,
+    };
+    match &stdout_file {
+        Some(f) => { cmd.stdout(f.try_clone()?); },
+        None => { cmd.stdout(Stdio::piped()); },
+    }
+
+    if redirects.stderr_to_stdout {
+        if let Some(f) = &stdout_file {
+            cmd.stderr(f.try_clone()?);
+        } else {
+            cmd.stderr(Stdio::piped());
+        }
+    } else if let Some(target) = &redirects.stderr {
+        cmd.stderr(open_redirect_target(target)?);
+    } else {
+        cmd.stderr(Stdio::piped());
+    }
+    Ok(())
+}
+
+// Drains a stage's piped stderr on a background thread and forwards it to the
+// real stderr, so discarding an earlier stage's `Child` (see `Pipe::pipe_to`
+// and `Job::spawn`) can't deadlock against a full, unread pipe, and earlier
+// stages' diagnostics stay visible even though they're no longer inherited.
+fn forward_stderr(child: &mut Child) {
+    if let Some(mut err) = child.stderr.take() {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = err.read_to_end(&mut buf);
+            let _ = std::io::stderr().write_all(&buf);
+        });
+    }
+}
+
+/// How a command relates to the one after it in a `;`/`&&`/`||` sequence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Connector {
+    Semicolon,
+    And,
+    Or,
+}
+
+/// A lexical token produced by [`tokenize`]. `Word` already has quoting,
+/// escaping and `${var}` expansion resolved, and back-to-back quoted/unquoted
+/// segments (`"a"'b'` -> `ab`) collapsed into one entry.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Pipe,
+    Semicolon,
+    And,
+    Or,
+    Background,
+    RedirectIn,
+    RedirectOut,
+    RedirectAppend,
+    RedirectErr,
+    RedirectErrAppend,
+    RedirectErrToOut,
+}
+
+fn is_metachar(c: char) -> bool {
+    matches!(c, ';' | '|' | '<' | '>')
+}
+
+fn expand_env(name: &str) -> String {
+    std::env::var(name).unwrap_or_default()
+}
+
+// Turns a command-line string into a flat token stream. Backslash escapes the
+// next char outside single quotes; single quotes are fully literal; double
+// quotes expand `${var}` via `resolve` but keep whitespace literal. `;`, `|`,
+// `<`, `>` always end the current word, even with no surrounding whitespace,
+// so e.g. `cmd>out` tokenizes the same as `cmd > out`.
+fn tokenize(s: &str) -> Vec<Token> {
+    tokenize_with(s, &expand_env)
+}
+
+// Same as `tokenize`, but `${var}` is looked up through `resolve` instead of
+// always going to the process environment; the `use`-variable form of
+// `run_cmd!`/`spawn_cmd!` passes a resolver backed by its symbol table so
+// that case shares this scanner's quote/escape handling instead of
+// re-implementing its own.
+fn tokenize_with(s: &str, resolve: &dyn Fn(&str) -> String) -> Vec<Token> {
+    let chars: Vec<char> = s.chars().collect();
+    let len = chars.len();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < len {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == ';' {
+            tokens.push(Token::Semicolon);
+            i += 1;
+            continue;
+        }
+        if c == '&' && i + 1 < len && chars[i + 1] == '&' {
+            tokens.push(Token::And);
+            i += 2;
+            continue;
+        }
+        if c == '|' && i + 1 < len && chars[i + 1] == '|' {
+            tokens.push(Token::Or);
+            i += 2;
+            continue;
+        }
+        if c == '|' {
+            tokens.push(Token::Pipe);
+            i += 1;
+            continue;
+        }
+        if c == '&' {
+            tokens.push(Token::Background);
+            i += 1;
+            continue;
+        }
+        if c == '2' && i + 3 < len && chars[i + 1] == '>' && chars[i + 2] == '&' && chars[i + 3] == '1' {
+            tokens.push(Token::RedirectErrToOut);
+            i += 4;
+            continue;
+        }
+        if c == '2' && i + 2 < len && chars[i + 1] == '>' && chars[i + 2] == '>' {
+            tokens.push(Token::RedirectErrAppend);
+            i += 3;
+            continue;
+        }
+        if c == '2' && i + 1 < len && chars[i + 1] == '>' {
+            tokens.push(Token::RedirectErr);
+            i += 2;
+            continue;
+        }
+        if c == '>' && i + 1 < len && chars[i + 1] == '>' {
+            tokens.push(Token::RedirectAppend);
+            i += 2;
+            continue;
+        }
+        if c == '>' {
+            tokens.push(Token::RedirectOut);
+            i += 1;
+            continue;
+        }
+        if c == '<' {
+            tokens.push(Token::RedirectIn);
+            i += 1;
+            continue;
+        }
+
+        let mut word = String::new();
+        let mut has_word = false;
+        while i < len {
+            let c = chars[i];
+            if c.is_whitespace() || is_metachar(c) {
+                break;
+            }
+            if c == '&' {
+                break;
+            }
+            has_word = true;
+            if c == '\'' {
+                i += 1;
+                while i < len && chars[i] != '\'' {
+                    word.push(chars[i]);
+                    i += 1;
+                }
+                i += 1;
+            } else if c == '"' {
+                i += 1;
+                while i < len && chars[i] != '"' {
+                    if chars[i] == '\\' && i + 1 < len {
+                        word.push(chars[i + 1]);
+                        i += 2;
+                    } else if chars[i] == '$' && i + 1 < len && chars[i + 1] == '{' {
+                        i += 2;
+                        let mut var = String::new();
+                        while i < len && chars[i] != '}' {
+                            var.push(chars[i]);
+                            i += 1;
+                        }
+                        i += 1;
+                        word.push_str(&resolve(&var));
+                    } else {
+                        word.push(chars[i]);
+                        i += 1;
+                    }
+                }
+                i += 1;
+            } else if c == '\\' && i + 1 < len {
+                word.push(chars[i + 1]);
+                i += 2;
+            } else if c == '$' && i + 1 < len && chars[i + 1] == '{' {
+                i += 2;
+                let mut var = String::new();
+                while i < len && chars[i] != '}' {
+                    var.push(chars[i]);
+                    i += 1;
+                }
+                i += 1;
+                word.push_str(&resolve(&var));
+            } else {
+                word.push(c);
+                i += 1;
+            }
+        }
+        if has_word {
+            tokens.push(Token::Word(word));
+        }
+    }
+    tokens
+}
+
+/// A single spawnable program: its argv and any I/O redirections.
+#[derive(Debug, Clone, Default)]
+struct AstCommand {
+    argv: Vec<String>,
+    redirects: Redirects,
+}
+
+/// One or more `AstCommand`s chained together with `|`, optionally detached
+/// with a trailing `&` so [`run_cmd`] spawns it and moves on without waiting.
+#[derive(Debug, Clone, Default)]
+struct AstPipeline {
+    commands: Vec<AstCommand>,
+    background: bool,
+}
+
+impl AstPipeline {
+    fn is_empty(&self) -> bool {
+        self.commands.iter().all(|c| c.argv.is_empty())
+    }
+
+    // Reconstructed source text, good enough for log/error messages; not
+    // necessarily byte-identical to what the user typed (quoting is gone).
+    fn display(&self) -> String {
+        self.commands.iter()
+            .map(|c| c.argv.join(" "))
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+}
+
+/// A full `;`/`&&`/`||` chain of pipelines, as parsed by [`parse_sequence`].
+#[derive(Debug, Clone, Default)]
+struct AstSequence {
+    stages: Vec<(AstPipeline, Connector)>,
+}
+
+fn parse_command_tokens(tokens: &[Token]) -> AstCommand {
+    let mut command = AstCommand::default();
+    let mut iter = tokens.iter();
+    while let Some(tok) = iter.next() {
+        match tok {
+            Token::Word(w) => command.argv.push(w.clone()),
+            Token::RedirectIn => if let Some(Token::Word(f)) = iter.next() {
+                command.redirects.stdin = Some(f.clone());
+            },
+            Token::RedirectOut => if let Some(Token::Word(f)) = iter.next() {
+                command.redirects.stdout = Some(RedirectTarget::Truncate(f.clone()));
+            },
+            Token::RedirectAppend => if let Some(Token::Word(f)) = iter.next() {
+                command.redirects.stdout = Some(RedirectTarget::Append(f.clone()));
+            },
+            Token::RedirectErr => if let Some(Token::Word(f)) = iter.next() {
+                command.redirects.stderr = Some(RedirectTarget::Truncate(f.clone()));
+            },
+            Token::RedirectErrAppend => if let Some(Token::Word(f)) = iter.next() {
+                command.redirects.stderr = Some(RedirectTarget::Append(f.clone()));
+            },
+            Token::RedirectErrToOut => command.redirects.stderr_to_stdout = true,
+            Token::Pipe | Token::Semicolon | Token::And | Token::Or | Token::Background => {}
+        }
+    }
+    command
+}
+
+// Builds an `AstPipeline`, dropping any stage with an empty argv (a leading,
+// trailing or doubled `|` leaves one behind, e.g. `"echo hi | "` or `" | echo
+// hi"`) rather than letting it reach `Command::new(&command.argv[0])` later
+// and panic. Mirrors the old `parse_argv`, which filtered blank segments the
+// same way.
+fn new_pipeline(mut commands: Vec<AstCommand>, background: bool) -> AstPipeline {
+    commands.retain(|c| !c.argv.is_empty());
+    AstPipeline { commands, background }
+}
+
+// Parses a single pipeline (commands joined by `|`, no `;`/`&&`/`||`).
+fn parse_pipeline(s: &str) -> AstPipeline {
+    parse_pipeline_tokens(tokenize(s))
+}
+
+fn parse_pipeline_tokens(tokens: Vec<Token>) -> AstPipeline {
+    let mut commands = Vec::new();
+    let mut stage_tokens = Vec::new();
+    for tok in tokens {
+        if tok == Token::Pipe {
+            commands.push(parse_command_tokens(&stage_tokens));
+            stage_tokens.clear();
+        } else {
+            stage_tokens.push(tok);
+        }
+    }
+    commands.push(parse_command_tokens(&stage_tokens));
+    new_pipeline(commands, false)
+}
+
+// Parses a full `;`/`&&`/`||` command sequence, each stage itself a pipeline.
+fn parse_sequence(s: &str) -> AstSequence {
+    parse_sequence_tokens(tokenize(s))
+}
+
+fn parse_sequence_tokens(tokens: Vec<Token>) -> AstSequence {
+    let mut stages = Vec::new();
+    let mut pipeline_commands = Vec::new();
+    let mut stage_tokens = Vec::new();
+
+    for tok in tokens {
+        match tok {
+            Token::Pipe => {
+                pipeline_commands.push(parse_command_tokens(&stage_tokens));
+                stage_tokens.clear();
+            }
+            Token::Semicolon | Token::And | Token::Or => {
+                pipeline_commands.push(parse_command_tokens(&stage_tokens));
+                stage_tokens.clear();
+                let connector = match tok {
+                    Token::Semicolon => Connector::Semicolon,
+                    Token::And => Connector::And,
+                    Token::Or => Connector::Or,
+                    _ => unreachable!(),
+                };
+                stages.push((new_pipeline(std::mem::take(&mut pipeline_commands), false), connector));
+            }
+            Token::Background => {
+                pipeline_commands.push(parse_command_tokens(&stage_tokens));
+                stage_tokens.clear();
+                let pipeline = new_pipeline(std::mem::take(&mut pipeline_commands), true);
+                // A connector after `&` doesn't depend on the backgrounded job's
+                // eventual status, since it hasn't finished yet; treat it like `;`.
+                stages.push((pipeline, Connector::Semicolon));
+            }
+            other => stage_tokens.push(other),
+        }
+    }
+    if !stage_tokens.is_empty() || !pipeline_commands.is_empty() {
+        pipeline_commands.push(parse_command_tokens(&stage_tokens));
+        stages.push((new_pipeline(pipeline_commands, false), Connector::Semicolon));
+    }
+    AstSequence { stages }
+}
+
+fn parse_single_command(s: &str) -> AstCommand {
+    parse_command_tokens(&tokenize(s))
+}
+
 ///
 /// pipe command could also lauched in builder style
 /// ```rust
 /// Pipe::new("du -ah .")?.pipe("sort -hr")?.pipe("head -n 5")?.wait_cmd_result()
 /// ```
 ///
+/// I/O redirections (`<`, `>`, `>>`, `2>`, `2>>`, `2>&1`) are recognized on each
+/// stage, e.g. `Pipe::new("grep foo < input.txt > out.txt 2>&1")`.
+///
 pub struct Pipe {
     last_proc: Child,
     full_cmd: String,
@@ -221,30 +688,93 @@ pub struct Pipe {
 
 impl Pipe {
     pub fn new(pipe_cmd: &str) -> PipeResult {
-        let args = parse_args(pipe_cmd);
-        let argv = parse_argv(&args);
+        Pipe::spawn_head(&parse_single_command(pipe_cmd), pipe_cmd.into())
+    }
+
+    /// Like `new`, but feeds `input` into the spawned process's stdin instead of
+    /// inheriting it, so in-memory data can be pushed through a pipeline, e.g.
+    /// `Pipe::with_input("sort", data)?.pipe("gzip")?.wait_bytes_result()`.
+    pub fn with_input(pipe_cmd: &str, input: impl Into<Vec<u8>>) -> PipeResult {
+        let command = parse_single_command(pipe_cmd);
+        if command.argv.is_empty() {
+            return Err(empty_command_error());
+        }
+
+        let mut cmd = Command::new(&command.argv[0]);
+        cmd.args(&command.argv[1..]);
+        cmd.stdin(Stdio::piped());
+
+        configure_stdout_stderr(&mut cmd, &command.redirects)?;
+
+        let mut child = cmd.spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin was piped");
+        let input = input.into();
+        thread::spawn(move || {
+            // A child that exits early (e.g. `head`) closes its end of the pipe;
+            // writing the rest would error, but that's not our problem to report.
+            let _ = stdin.write_all(&input);
+        });
 
         Ok(Pipe {
-            last_proc: Command::new(&argv[0])
-                        .args(&argv[1..])
-                        .stdout(Stdio::piped())
-                        .spawn()?,
+            last_proc: child,
             full_cmd: pipe_cmd.into(),
         })
     }
 
     pub fn pipe(&mut self, pipe_cmd: &str) -> PipeResult {
-        let args = parse_args(pipe_cmd);
-        let argv = parse_argv(&args);
-        let new_proc = Command::new(&argv[0])
-                        .args(&argv[1..])
-                        .stdin(self.last_proc.stdout.take().unwrap())
-                        .stdout(Stdio::piped())
-                        .spawn()?;
+        let full_cmd = format!("{} | {}", self.full_cmd, pipe_cmd);
+        self.pipe_to(&parse_single_command(pipe_cmd), full_cmd)
+    }
+
+    fn spawn_head(command: &AstCommand, full_cmd: String) -> PipeResult {
+        if command.argv.is_empty() {
+            return Err(empty_command_error());
+        }
+        let mut cmd = Command::new(&command.argv[0]);
+        cmd.args(&command.argv[1..]);
+
+        if let Some(path) = &command.redirects.stdin {
+            cmd.stdin(File::open(path)?);
+        }
+
+        configure_stdout_stderr(&mut cmd, &command.redirects)?;
+
+        Ok(Pipe {
+            last_proc: cmd.spawn()?,
+            full_cmd,
+        })
+    }
+
+    fn pipe_to(&mut self, command: &AstCommand, full_cmd: String) -> PipeResult {
+        if command.argv.is_empty() {
+            return Err(empty_command_error());
+        }
+        let mut cmd = Command::new(&command.argv[0]);
+        cmd.args(&command.argv[1..]);
+        if let Some(path) = &command.redirects.stdin {
+            // A `<` redirect on this stage overrides the pipe from the previous
+            // one; drop our end of that pipe too, so the previous stage isn't
+            // left blocked writing into a pipe nobody will ever read.
+            cmd.stdin(File::open(path)?);
+            self.last_proc.stdout.take();
+        } else if let Some(prev_stdout) = self.last_proc.stdout.take() {
+            cmd.stdin(prev_stdout);
+        } else {
+            // The previous stage's stdout was redirected to a file (or
+            // otherwise isn't a pipe), so there's nothing to feed this stage;
+            // a real shell gives it closed/empty stdin here, not a stray
+            // inherit of the real process's stdin.
+            cmd.stdin(Stdio::null());
+        }
+
+        configure_stdout_stderr(&mut cmd, &command.redirects)?;
+
+        let new_proc = cmd.spawn()?;
+        forward_stderr(&mut self.last_proc);
         self.last_proc.wait()?;
         Ok(Pipe {
             last_proc: new_proc,
-            full_cmd: format!("{} | {}", self.full_cmd, pipe_cmd),
+            full_cmd,
         })
     }
 
@@ -254,49 +784,321 @@ impl Pipe {
     }
 
     pub fn wait_fun_result(self) ->FunResult {
+        let output = self.finish()?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// Like `wait_fun_result`, but returns the raw stdout bytes without lossy
+    /// UTF-8 conversion, for pipelines that produce binary output.
+    pub fn wait_bytes_result(self) -> Result<Vec<u8>, Error> {
+        let output = self.finish()?;
+        Ok(output.stdout)
+    }
+
+    /// Like `wait_fun_result`, but returns the tail stage's stderr instead of
+    /// printing it or folding it into the error, with both streams alongside the exit status,
+    /// without turning a non-zero exit into an `Err`.
+    pub fn wait_full_result(self) -> Result<FullOutput, Error> {
+        info!("Running \"{}\" ...", self.full_cmd.trim());
+        let output = self.last_proc.wait_with_output()?;
+        Ok(FullOutput {
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+
+    fn finish(self) -> Result<Output, Error> {
         info!("Running \"{}\" ...", self.full_cmd.trim());
+        let full_cmd = self.full_cmd;
         let output = self.last_proc.wait_with_output()?;
         if !output.status.success() {
-            Err(to_io_error(&self.full_cmd, output.status))
+            Err(to_io_error(&full_cmd, output.status, &output.stderr))
         } else {
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            // Nobody asked for wait_full_result, so nobody else will ever see this
+            // stderr; print it like the inherited-stderr baseline did, instead of
+            // swallowing it now that it's captured.
+            let _ = std::io::stderr().write_all(&output.stderr);
+            Ok(output)
         }
     }
 }
 
-fn run_pipe_cmd(full_command: &str) -> CmdResult {
-    result_fun_to_cmd(run_pipe_fun(full_command))
+/// The result of [`Pipe::wait_full_result`]: the tail stage's exit status
+/// alongside both of its captured streams.
+pub struct FullOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+// Reads a pipe to completion on a background thread and hands back the bytes
+// through the returned `JoinHandle`, the same way `forward_stderr` drains a
+// non-tail stage, except the tail stage's bytes are kept instead of being
+// forwarded to stderr: the caller may still want them from `wait_fun`/`wait`.
+fn drain_to_buffer(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// A pipeline launched in the background by `spawn_cmd!`/`spawn_fun!`, or by a
+/// trailing `&` parsed out of `run_cmd!`. Holds every stage's `Child`, so
+/// unlike `Pipe` it can be polled or killed instead of only ever waited on.
+pub struct Job {
+    procs: Vec<Child>,
+    tail_stdout: thread::JoinHandle<Vec<u8>>,
+    tail_stderr: thread::JoinHandle<Vec<u8>>,
+    full_cmd: String,
 }
 
-fn run_pipe_fun(full_command: &str) -> FunResult {
-    let pipe_args = parse_pipes(full_command.trim());
-    let pipe_argv = parse_argv(&pipe_args);
+impl Job {
+    fn spawn(pipeline: &AstPipeline) -> Result<Job, Error> {
+        let full_cmd = pipeline.display();
+        let mut iter = pipeline.commands.iter();
+        let head = iter.next().ok_or_else(empty_command_error)?;
+
+        let mut cmd = Command::new(&head.argv[0]);
+        cmd.args(&head.argv[1..]);
+        if let Some(path) = &head.redirects.stdin {
+            cmd.stdin(File::open(path)?);
+        }
+        configure_stdout_stderr(&mut cmd, &head.redirects)?;
+
+        let mut procs = vec![cmd.spawn()?];
+        for command in iter {
+            let mut cmd = Command::new(&command.argv[0]);
+            cmd.args(&command.argv[1..]);
+            let prev = procs.last_mut().unwrap();
+            if let Some(path) = &command.redirects.stdin {
+                // A `<` redirect on this stage overrides the pipe from the previous
+                // one; drop our end of that pipe too, so `prev` isn't left blocked
+                // writing into a pipe nobody will ever read.
+                cmd.stdin(File::open(path)?);
+                prev.stdout.take();
+            } else if let Some(prev_stdout) = prev.stdout.take() {
+                cmd.stdin(prev_stdout);
+            } else {
+                // `prev`'s stdout was redirected to a file (or otherwise isn't
+                // a pipe), so there's nothing to feed this stage; give it
+                // closed/empty stdin instead of a stray inherit.
+                cmd.stdin(Stdio::null());
+            }
+            // Nobody will wait on `prev` until (or unless) the caller calls
+            // `wait`/`wait_fun`, which may be much later or never; forward its
+            // stderr now so it can't block on a full, unread pipe meanwhile.
+            forward_stderr(prev);
+            configure_stdout_stderr(&mut cmd, &command.redirects)?;
+            procs.push(cmd.spawn()?);
+        }
+
+        // Same reasoning as `forward_stderr` above, but for the tail stage:
+        // nobody reads its stdout/stderr until (or unless) `wait`/`wait_fun`
+        // is called, which may be much later or never, and `try_wait`/`kill`
+        // don't read at all. Drain both into a buffer now so a chatty
+        // backgrounded command can't fill its pipe and block forever on a
+        // write the caller was only ever going to poll for, not read.
+        let tail = procs.last_mut().expect("a job always has at least one process");
+        // A stream sent to a file by `>`, `2>` or `2>&1` has no pipe to drain;
+        // its buffer is then empty.
+        let tail_stdout = match tail.stdout.take() {
+            Some(out) => drain_to_buffer(out),
+            None => thread::spawn(Vec::new),
+        };
+        let tail_stderr = match tail.stderr.take() {
+            Some(err) => drain_to_buffer(err),
+            None => thread::spawn(Vec::new),
+        };
+
+        info!("Running \"{}\" in background ...", full_cmd.trim());
+        Ok(Job { procs, tail_stdout, tail_stderr, full_cmd })
+    }
+
+    /// Blocks until every stage has exited, then prints the last stage's stdout.
+    pub fn wait(self) -> CmdResult {
+        result_fun_to_cmd(self.wait_fun())
+    }
 
-    let mut last_proc = Pipe::new(pipe_argv[0])?;
-    for (i, pipe_cmd) in pipe_argv.iter().enumerate() {
-        if i != 0 {
-            last_proc = last_proc.pipe(pipe_cmd)?;
+    /// Blocks until every stage has exited and returns the last stage's stdout.
+    pub fn wait_fun(mut self) -> FunResult {
+        let full_cmd = self.full_cmd;
+        let mut last = self.procs.pop().expect("a job always has at least one process");
+        // Wait on every earlier stage even if one of them errors, so `last`
+        // (already pulled out above) still gets waited on instead of leaking a
+        // zombie process on an early return.
+        let mut wait_err = None;
+        for mut proc in self.procs {
+            if let Err(e) = proc.wait() {
+                wait_err.get_or_insert(e);
+            }
+        }
+        let status = last.wait()?;
+        let stdout = self.tail_stdout.join().unwrap_or_default();
+        let stderr = self.tail_stderr.join().unwrap_or_default();
+        if let Some(e) = wait_err {
+            return Err(e);
+        }
+        if !status.success() {
+            Err(to_io_error(&full_cmd, status, &stderr))
+        } else {
+            Ok(String::from_utf8_lossy(&stdout).to_string())
         }
     }
 
+    /// Polls the last stage without blocking; earlier stages are left running.
+    /// Safe to call even while the tail stage is still writing a lot of
+    /// output, since its stdout/stderr are drained in the background rather
+    /// than only read once `wait`/`wait_fun` is called.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+        self.procs.last_mut().expect("a job always has at least one process").try_wait()
+    }
+
+    /// Kills every stage of the job.
+    pub fn kill(&mut self) -> Result<(), Error> {
+        for proc in &mut self.procs {
+            proc.kill()?;
+        }
+        Ok(())
+    }
+}
+
+// A pipeline with no commands left after `new_pipeline` filtered out blank
+// stages (e.g. the whole string was just `"|"`) has nothing to run; surface
+// that as a normal error instead of panicking on the first `.next()`.
+fn empty_command_error() -> Error {
+    Error::new(ErrorKind::InvalidInput, "empty command")
+}
+
+fn run_ast_pipeline(pipeline: &AstPipeline) -> FunResult {
+    let full_cmd = pipeline.display();
+    let mut iter = pipeline.commands.iter();
+    let head = iter.next().ok_or_else(empty_command_error)?;
+
+    let mut last_proc = Pipe::spawn_head(head, full_cmd.clone())?;
+    for command in iter {
+        last_proc = last_proc.pipe_to(command, full_cmd.clone())?;
+    }
+
     last_proc.wait_fun_result()
 }
 
+fn run_ast_pipeline_cmd(pipeline: &AstPipeline) -> CmdResult {
+    result_fun_to_cmd(run_ast_pipeline(pipeline))
+}
+
+fn run_ast_pipeline_full(pipeline: &AstPipeline) -> Result<FullOutput, Error> {
+    let full_cmd = pipeline.display();
+    let mut iter = pipeline.commands.iter();
+    let head = iter.next().ok_or_else(empty_command_error)?;
+
+    let mut last_proc = Pipe::spawn_head(head, full_cmd.clone())?;
+    for command in iter {
+        last_proc = last_proc.pipe_to(command, full_cmd.clone())?;
+    }
+
+    last_proc.wait_full_result()
+}
+
 #[doc(hidden)]
 pub fn run_fun(cmds: &str) -> FunResult {
-    run_pipe_fun(cmds)
+    run_ast_pipeline(&parse_pipeline(cmds.trim()))
+}
+
+#[doc(hidden)]
+pub fn run_fun_all(cmds: &str) -> Result<FullOutput, Error> {
+    run_ast_pipeline_full(&parse_pipeline(cmds.trim()))
+}
+
+fn run_cmd_sequence(sequence: AstSequence) -> CmdResult {
+    // `pending` is the connector that preceded `pipeline`; it decides whether the
+    // pipeline runs at all. `prev_succeeded` is the status of the last pipeline
+    // actually run, and keeps flowing through stages skipped by && or ||, so that
+    // e.g. `false && a || b` runs `b` because `false` (not the skipped `a`) failed.
+    let mut result: CmdResult = Ok(());
+    let mut prev_succeeded = true;
+    let mut pending = Connector::Semicolon;
+    for (pipeline, next_connector) in sequence.stages {
+        if !pipeline.is_empty() {
+            let run = match pending {
+                Connector::Semicolon => true,
+                Connector::And => prev_succeeded,
+                Connector::Or => !prev_succeeded,
+            };
+            if run {
+                if pipeline.background {
+                    // Fire and forget: spawning is the only thing that can fail here,
+                    // since nothing downstream waits on the job's exit status.
+                    Job::spawn(&pipeline)?;
+                    result = Ok(());
+                    prev_succeeded = true;
+                } else {
+                    result = run_ast_pipeline_cmd(&pipeline);
+                    prev_succeeded = result.is_ok();
+                }
+            }
+        }
+        pending = next_connector;
+    }
+    result
 }
 
 #[doc(hidden)]
 pub fn run_cmd(cmds: &str) -> CmdResult {
-    let cmd_args = parse_cmds(cmds);
-    let cmd_argv = parse_argv(&cmd_args);
-    for cmd in cmd_argv {
-        if let Err(e) = run_pipe_cmd(cmd) {
-            return Err(e);
+    run_cmd_sequence(parse_sequence(cmds))
+}
+
+// Strips a leading `use var, var2;` declaration off the raw macro source text
+// (already consumed by `run_cmd!`/`spawn_cmd!`'s `use` expansion) so the rest
+// can be tokenized as plain command text.
+fn strip_use_prefix(src: &str) -> &str {
+    let trimmed = src.trim_start();
+    if trimmed.starts_with("use ") || trimmed.starts_with("use\t") {
+        match trimmed.find(';') {
+            Some(idx) => &trimmed[idx + 1..],
+            None => trimmed,
         }
+    } else {
+        trimmed
     }
-    Ok(())
+}
+
+// Builds the `${var}` resolver for the `use`-variable form of `run_cmd!`/
+// `spawn_cmd!`: looks the name up in the symbol table `use` bound it into,
+// dying with the same diagnostic `resolve_name` used to give on an unknown name.
+fn var_resolver<'a>(
+    st: &'a HashMap<String, String>,
+    src: &'a str,
+    file: &'a str,
+    line: u32,
+) -> impl Fn(&str) -> String + 'a {
+    move |var: &str| match st.get(var) {
+        Some(v) => v.clone(),
+        None => die!("resolve {} failed, {}:{}\n{}", var, file, line, src),
+    }
+}
+
+#[doc(hidden)]
+pub fn run_cmd_with_vars(src: &str, st: &HashMap<String, String>, file: &str, line: u32) -> CmdResult {
+    let tokens = tokenize_with(strip_use_prefix(src), &var_resolver(st, src, file, line));
+    run_cmd_sequence(parse_sequence_tokens(tokens))
+}
+
+#[doc(hidden)]
+pub fn spawn_cmd_with_vars(src: &str, st: &HashMap<String, String>, file: &str, line: u32) -> Result<Job, Error> {
+    let tokens = tokenize_with(strip_use_prefix(src), &var_resolver(st, src, file, line));
+    Job::spawn(&parse_pipeline_tokens(tokens))
+}
+
+#[doc(hidden)]
+pub fn spawn_cmd(cmds: &str) -> Result<Job, Error> {
+    Job::spawn(&parse_pipeline(cmds.trim()))
+}
+
+#[doc(hidden)]
+pub fn spawn_fun(cmds: &str) -> Result<Job, Error> {
+    spawn_cmd(cmds)
 }
 
 fn result_fun_to_cmd(res: FunResult) -> CmdResult {
@@ -309,129 +1111,202 @@ fn result_fun_to_cmd(res: FunResult) -> CmdResult {
     }
 }
 
-fn to_io_error(command: &str, status: ExitStatus) -> Error {
-    if let Some(code) = status.code() {
-        Error::new(ErrorKind::Other, format!("{} exit with {}", command, code))
+fn to_io_error(command: &str, status: ExitStatus, stderr: &[u8]) -> Error {
+    let reason = match status.code() {
+        Some(code) => format!("{} exit with {}", command, code),
+        None => "Unknown error".to_string(),
+    };
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        Error::new(ErrorKind::Other, reason)
     } else {
-        Error::new(ErrorKind::Other, "Unknown error")
-    }
-}
-
-fn parse_args(s: &str) -> String {
-    let mut in_single_quote = false;
-    let mut in_double_quote = false;
-    s.chars()
-        .map(|c| {
-            if c == '"' && !in_single_quote {
-                in_double_quote = !in_double_quote;
-                '\n'
-            } else if c == '\'' && !in_double_quote {
-                in_single_quote = !in_single_quote;
-                '\n'
-            } else if !in_single_quote && !in_double_quote && char::is_whitespace(c) {
-                '\n'
-            } else {
-                c
-            }
-        })
-        .collect()
+        Error::new(ErrorKind::Other, format!("{}: {}", reason, stderr))
+    }
 }
 
-fn parse_cmds(s: &str) -> String {
-    parse_seps(s, ';')
-}
+#[cfg(test)]
+mod redirect_tests {
+    use super::*;
+
+    #[test]
+    fn stdin_redirect_on_head_command() {
+        let command = parse_single_command("cat < input.txt");
+        assert_eq!(command.argv, vec!["cat"]);
+        assert_eq!(command.redirects.stdin, Some("input.txt".to_string()));
+    }
 
-fn parse_pipes(s: &str) -> String {
-    parse_seps(s, '|')
+    #[test]
+    fn stdout_truncate_and_append() {
+        let truncate = parse_single_command("echo hi > out.txt");
+        assert!(matches!(truncate.redirects.stdout, Some(RedirectTarget::Truncate(ref p)) if p == "out.txt"));
+
+        let append = parse_single_command("echo hi >> out.txt");
+        assert!(matches!(append.redirects.stdout, Some(RedirectTarget::Append(ref p)) if p == "out.txt"));
+    }
+
+    #[test]
+    fn stderr_redirect_and_merge_to_stdout() {
+        let command = parse_single_command("cmd 2> err.txt");
+        assert!(matches!(command.redirects.stderr, Some(RedirectTarget::Truncate(ref p)) if p == "err.txt"));
+
+        let merged = parse_single_command("cmd 2>&1");
+        assert!(merged.redirects.stderr_to_stdout);
+    }
+
+    #[test]
+    fn stdin_redirect_on_non_head_pipeline_stage() {
+        // A `<` on a later pipeline stage must still be recorded on that
+        // stage's own AstCommand, not silently dropped.
+        let pipeline = parse_pipeline("cat a.txt | wc -l < b.txt");
+        assert_eq!(pipeline.commands[1].redirects.stdin, Some("b.txt".to_string()));
+    }
+
+    #[test]
+    fn redirect_with_no_surrounding_whitespace() {
+        let command = parse_single_command("cmd>out.txt");
+        assert!(matches!(command.redirects.stdout, Some(RedirectTarget::Truncate(ref p)) if p == "out.txt"));
+    }
 }
 
-fn parse_seps(s: &str, sep: char) -> String {
-    let mut in_single_quote = false;
-    let mut in_double_quote = false;
-    s.chars()
-        .map(|c| {
-            if c == '"' && !in_single_quote {
-                in_double_quote = !in_double_quote;
-            } else if c == '\'' && !in_double_quote {
-                in_single_quote = !in_single_quote;
-            }
+#[cfg(test)]
+mod short_circuit_tests {
+    use super::*;
 
-            if c == sep && !in_single_quote && !in_double_quote {
-                '\n'
-            } else {
-                c
-            }
-        })
-        .collect()
+    #[test]
+    fn and_runs_rhs_only_on_success() {
+        assert!(run_cmd("true && echo ran > /tmp/rust_cmd_lib_and_ran").is_ok());
+        assert!(std::path::Path::new("/tmp/rust_cmd_lib_and_ran").exists());
+        let _ = std::fs::remove_file("/tmp/rust_cmd_lib_and_ran");
+
+        assert!(run_cmd("false && echo ran > /tmp/rust_cmd_lib_and_skipped").is_err());
+        assert!(!std::path::Path::new("/tmp/rust_cmd_lib_and_skipped").exists());
+    }
+
+    #[test]
+    fn or_runs_rhs_only_on_failure() {
+        assert!(run_cmd("true || echo ran > /tmp/rust_cmd_lib_or_skipped").is_ok());
+        assert!(!std::path::Path::new("/tmp/rust_cmd_lib_or_skipped").exists());
+
+        assert!(run_cmd("false || echo ran > /tmp/rust_cmd_lib_or_ran").is_ok());
+        assert!(std::path::Path::new("/tmp/rust_cmd_lib_or_ran").exists());
+        let _ = std::fs::remove_file("/tmp/rust_cmd_lib_or_ran");
+    }
+
+    #[test]
+    fn failure_propagates_through_skipped_stage() {
+        // `false && a || b` must run `b`, because the status feeding `||` is
+        // `false`'s (the skipped `a` never ran to produce one of its own).
+        assert!(run_cmd("false && echo a > /tmp/rust_cmd_lib_chain_a || echo b > /tmp/rust_cmd_lib_chain_b").is_ok());
+        assert!(!std::path::Path::new("/tmp/rust_cmd_lib_chain_a").exists());
+        assert!(std::path::Path::new("/tmp/rust_cmd_lib_chain_b").exists());
+        let _ = std::fs::remove_file("/tmp/rust_cmd_lib_chain_b");
+    }
 }
 
-fn parse_argv(s: &str) -> Vec<&str> {
-    s.split("\n")
-        .filter(|s| !s.trim().is_empty())
-        .collect::<Vec<&str>>()
+#[cfg(test)]
+mod job_tests {
+    use super::*;
+
+    #[test]
+    fn wait_blocks_until_done() {
+        let job = Job::spawn(&parse_pipeline("true")).unwrap();
+        assert!(job.wait().is_ok());
+    }
+
+    #[test]
+    fn wait_fun_returns_tail_stage_stdout() {
+        let job = Job::spawn(&parse_pipeline("echo hi | cat")).unwrap();
+        assert_eq!(job.wait_fun().unwrap().trim(), "hi");
+    }
+
+    #[test]
+    fn wait_fun_waits_on_every_stage_not_just_the_tail() {
+        // Regression test: `wait_fun` must wait on every earlier stage even
+        // though it pops `last` out first, otherwise an earlier stage leaks
+        // as a zombie process.
+        let job = Job::spawn(&parse_pipeline("sleep 0.2 | cat")).unwrap();
+        assert!(job.wait_fun().is_ok());
+    }
+
+    #[test]
+    fn try_wait_is_none_while_running_then_some_when_done() {
+        let mut job = Job::spawn(&parse_pipeline("sleep 0.2")).unwrap();
+        assert_eq!(job.try_wait().unwrap(), None);
+        std::thread::sleep(std::time::Duration::from_millis(400));
+        assert!(job.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn kill_stops_a_running_job() {
+        let mut job = Job::spawn(&parse_pipeline("sleep 30")).unwrap();
+        assert!(job.kill().is_ok());
+        assert!(job.wait().is_err());
+    }
 }
 
-#[doc(hidden)]
-pub fn resolve_name(src: &str, st: &HashMap<String,String>, file: &str, line: u32) -> String {
-    let mut output = String::new();
-    let input: Vec<char> = src.chars().collect();
-    let len = input.len();
-    let mut in_single_quote = false;
-    let mut in_double_quote = false;
+#[cfg(test)]
+mod pipe_input_tests {
+    use super::*;
 
-    let mut i = 0;
-    while i < len {
-        if i == 0 { // skip variable declaration part
-            while input[i] == ' ' || input[i] == '\t' || input[i] == '\n' {
-                i += 1;
-            }
-            let first = input[i..i+4].iter().collect::<String>();
-            if i < len-4 && first == "use " || first == "use\t" {
-                while input[i] != ';' {
-                    i += 1;
-                }
-            }
-        }
+    #[test]
+    fn with_input_feeds_stdin_instead_of_inheriting_it() {
+        let out = Pipe::with_input("cat", "hello").unwrap().wait_fun_result().unwrap();
+        assert_eq!(out, "hello");
+    }
 
-        if input[i] == '"' && !in_single_quote {
-            in_double_quote = !in_double_quote;
-        } else if input[i] == '\'' && !in_double_quote {
-            in_single_quote = !in_single_quote;
-        }
+    #[test]
+    fn with_input_pipes_into_further_stages() {
+        let out = Pipe::with_input("cat", "hello world")
+            .unwrap()
+            .pipe("wc -w")
+            .unwrap()
+            .wait_fun_result()
+            .unwrap();
+        assert_eq!(out.trim(), "2");
+    }
 
-        if !in_single_quote && i < len-2 &&
-           input[i] == '$' && input[i+1] == '{' {
-            i += 2;
-            let mut var = String::new();
-            while input[i] != '}' {
-                var.push(input[i]);
-                if input[i] == ';' || input[i] == '\n' || i == len-1 {
-                    die!("invalid name {}, {}:{}\n{}", var, file, line, src);
-                }
-                i += 1;
-            }
-            match st.get(&var) {
-                None => {
-                    die!("resolve {} failed, {}:{}\n{}", var, file, line, src);
-                },
-                Some(v) => {
-                    if in_double_quote {
-                        output += v;
-                    } else {
-                        output += "\"";
-                        output += v;
-                        output += "\"";
-                    }
-                }
-            }
-        } else {
-            output.push(input[i]);
-        }
-        i += 1;
+    #[test]
+    fn wait_bytes_result_returns_raw_stdout() {
+        let bytes = Pipe::with_input("cat", vec![0u8, 159, 146, 150])
+            .unwrap()
+            .wait_bytes_result()
+            .unwrap();
+        assert_eq!(bytes, vec![0u8, 159, 146, 150]);
     }
 
-    output
+    #[test]
+    fn with_input_on_empty_command_errors_instead_of_panicking() {
+        assert!(Pipe::with_input("", "hello").is_err());
+    }
 }
 
+#[cfg(test)]
+mod full_result_tests {
+    use super::*;
+
+    #[test]
+    fn wait_full_result_captures_both_streams_on_success() {
+        let output = run_fun_all(r#"sh -c "echo out; echo err >&2""#).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout.trim(), "out");
+        assert_eq!(output.stderr.trim(), "err");
+    }
+
+    #[test]
+    fn wait_full_result_does_not_turn_failure_into_err() {
+        // Unlike `wait_fun_result`/`wait_cmd_result`, a non-zero exit is
+        // reported through `status`, not `Err`.
+        let output = run_fun_all("false").unwrap();
+        assert!(!output.status.success());
+    }
+
+    #[test]
+    fn run_fun_all_runs_a_full_pipeline() {
+        let output = run_fun_all("echo the quick brown fox | wc -w").unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout.trim(), "4");
+    }
+}
 
 